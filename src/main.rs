@@ -1,22 +1,26 @@
+use std::collections::{hash_map::DefaultHasher, HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+
 use bevy::{
     color::palettes::tailwind,
     prelude::*,
     window::{CursorGrabMode, PrimaryWindow},
 };
+use bevy_xpbd_3d::prelude::*;
 use leafwing_input_manager::{
-    prelude::{ActionState, DualAxis, InputMap},
+    prelude::{ActionState, DualAxis, InputMap, MouseWheelAxisType, SingleAxis},
     Actionlike,
 };
 use lightyear::{
     client::{config::ClientConfig, plugin::ClientPlugins},
     prelude::{
         client::{
-            ClientCommands, ComponentSyncMode, LerpFn, VisualInterpolateStatus,
-            VisualInterpolationPlugin,
+            ClientCommands, ComponentSyncMode, Confirmed, ConnectionManager, LerpFn, Predicted,
+            Rollback, TickManager, VisualInterpolateStatus, VisualInterpolationPlugin,
         },
         server::{Replicate, ServerCommands, SyncTarget},
         AppComponentExt, ChannelDirection, ClientId, Deserialize, LeafwingInputPlugin, Mode,
-        NetworkTarget, Serialize, SharedConfig,
+        NetworkTarget, Serialize, SharedConfig, Tick,
     },
     server::{config::ServerConfig, plugin::ServerPlugins},
     utils::bevy::TransformLinearInterpolation,
@@ -48,16 +52,69 @@ fn main() {
     app.add_plugins(LeafwingInputPlugin::<PlayerActions>::default());
     app.add_plugins(VisualInterpolationPlugin::<Transform>::default());
 
+    // Step physics inside `FixedUpdate` on a fixed single substep so that it runs
+    // tick-locked and is fully re-simulatable during prediction rollback.
+    app.add_plugins(PhysicsPlugins::new(FixedUpdate));
+    app.insert_resource(SubstepCount(1));
+
+    app.insert_resource(PredictionTuning::default());
+    app.insert_resource(SyncTestConfig::default());
+    app.init_resource::<PredictedChecksums>();
+    app.init_resource::<ConfirmedChecksums>();
+    app.insert_resource(LagCompensationConfig::default());
+
     app.register_component::<Player>(ChannelDirection::ClientToServer);
     app.register_component::<Transform>(ChannelDirection::ServerToClient)
         .add_prediction(ComponentSyncMode::Full)
         .add_interpolation_fn(TransformLinearInterpolation::lerp)
         .add_correction_fn(TransformLinearInterpolation::lerp);
+    app.register_component::<LinearVelocity>(ChannelDirection::ServerToClient)
+        .add_prediction(ComponentSyncMode::Full);
+    app.register_component::<AngularVelocity>(ChannelDirection::ServerToClient)
+        .add_prediction(ComponentSyncMode::Full);
+    app.register_component::<Position>(ChannelDirection::ServerToClient)
+        .add_prediction(ComponentSyncMode::Full);
+    app.register_component::<Rotation>(ChannelDirection::ServerToClient)
+        .add_prediction(ComponentSyncMode::Full);
 
     app.add_systems(Startup, set_up);
-    app.add_systems(PostStartup, grab_cursor);
+    app.add_systems(PostStartup, (grab_cursor, warn_sync_test_host_server_limitation));
 
-    app.add_systems(FixedUpdate, apply_actions);
+    // On rollback, lightyear restores `Transform` to the corrected historical
+    // value before re-running `FixedUpdate`; seed xpbd's own `Position`/`Rotation`
+    // from it so the re-simulated physics step starts from the corrected state.
+    // Only needed on a rollback tick — on a normal tick `Position`/`Rotation` are
+    // already where last step's xpbd sync left them.
+    app.add_systems(
+        FixedUpdate,
+        sync_physics_from_transform
+            .before(apply_actions)
+            .run_if(|rollback: Res<Rollback>| rollback.is_rollback()),
+    );
+    app.add_systems(FixedUpdate, sample_delayed_input.before(apply_actions));
+    // Runs in `Update`, not `FixedUpdate`: once it freezes the virtual clock no
+    // fixed tick runs, so a `FixedUpdate` system could never unfreeze it.
+    app.add_systems(Update, stall_past_prediction_window);
+    // Must land before xpbd's own step runs this tick, or whether this tick's
+    // velocity/rotation get integrated now or next tick is schedule-ambiguous —
+    // unacceptable for a determinism example.
+    app.add_systems(FixedUpdate, apply_actions.before(PhysicsSet::StepSimulation));
+    app.add_systems(
+        FixedUpdate,
+        (checksum_predicted_state, checksum_confirmed_state, reconcile_sync_test_checksums)
+            .chain()
+            .after(apply_actions)
+            .run_if(|config: Res<SyncTestConfig>| config.enabled),
+    );
+    app.add_systems(
+        FixedUpdate,
+        (update_lag_estimate, record_transform_history, resolve_fire_lag_compensated)
+            .chain()
+            .after(apply_actions)
+            .run_if(|config: Res<LagCompensationConfig>| config.enabled),
+    );
+
+    app.add_systems(Update, (update_camera_rig_look, camera_follow).chain());
 
     app.run();
 }
@@ -70,19 +127,37 @@ fn set_up(
     commands.start_server();
     commands.connect_client();
 
-    commands.spawn(PbrBundle {
-        mesh: meshes.add(Circle::new(50.0)),
-        material: materials.add(Color::WHITE),
-        transform: Transform::from_rotation(Quat::from_rotation_x(-std::f32::consts::FRAC_PI_2)),
-        ..default()
-    });
+    // xpbd derives the half-space normal from the body's own rotation, so the
+    // body itself must stay unrotated (local +Y == world up); the visual
+    // circle mesh needs the -90° tilt to lie flat, so that rotation goes on an
+    // unrelated child instead of the physics body.
+    commands
+        .spawn((
+            SpatialBundle::default(),
+            RigidBody::Static,
+            Collider::half_space(Vec3::Y),
+        ))
+        .with_children(|parent| {
+            parent.spawn(PbrBundle {
+                mesh: meshes.add(Circle::new(50.0)),
+                material: materials.add(Color::WHITE),
+                transform: Transform::from_rotation(Quat::from_rotation_x(
+                    -std::f32::consts::FRAC_PI_2,
+                )),
+                ..default()
+            });
+        });
 
-    commands.spawn(PbrBundle {
-        mesh: meshes.add(Cuboid::new(2.0, 2., 2.)),
-        material: materials.add(Color::Srgba(tailwind::YELLOW_400)),
-        transform: Transform::from_translation(Vec3::new(0., 10., 0.)),
-        ..default()
-    });
+    commands.spawn((
+        PbrBundle {
+            mesh: meshes.add(Cuboid::new(2.0, 2., 2.)),
+            material: materials.add(Color::Srgba(tailwind::YELLOW_400)),
+            transform: Transform::from_translation(Vec3::new(0., 10., 0.)),
+            ..default()
+        },
+        RigidBody::Dynamic,
+        Collider::cuboid(2.0, 2.0, 2.0),
+    ));
 
     commands.spawn(PointLightBundle {
         point_light: PointLight {
@@ -104,8 +179,10 @@ fn set_up(
         (PlayerActions::Right, KeyCode::ArrowRight),
     ]);
     input_map.insert(PlayerActions::Look, DualAxis::mouse_motion());
+    input_map.insert(PlayerActions::Fire, MouseButton::Left);
+    input_map.insert(PlayerActions::Zoom, SingleAxis::mouse_wheel(MouseWheelAxisType::Y));
 
-    commands
+    let player = commands
         .spawn((
             Player(ClientId::Local(0)),
             Replicate {
@@ -118,56 +195,616 @@ fn set_up(
             input_map,
             TransformBundle::default(),
             VisualInterpolateStatus::<Transform>::default(),
+            DelayedInputBuffer::default(),
+            TransformHistory::default(),
+            LagEstimate::default(),
+            RigidBody::Dynamic,
+            Collider::capsule(1.0, 0.5),
+            LockedAxes::new().lock_rotation_x().lock_rotation_z(),
+            LinearVelocity::default(),
+            AngularVelocity::default(),
         ))
-        .with_children(|parent| {
-            parent.spawn(Camera3dBundle {
-                transform: Transform::from_translation(Vec3::new(0., 10., 0.)),
-                ..default()
-            });
-        });
+        .id();
+
+    // The camera rig is deliberately not a child of the replicated player and
+    // carries no `Replicate`: pitch/zoom are purely client-local view state and
+    // must never get entangled with network sync.
+    commands.spawn((
+        Camera3dBundle {
+            transform: Transform::from_translation(Vec3::new(0., 10., 0.)),
+            ..default()
+        },
+        CameraRig {
+            follow: player,
+            pitch: 0.0,
+            zoom: CameraRig::DEFAULT_ZOOM,
+        },
+    ));
+}
+
+/// Tunable knobs for the input-delay / prediction-window tradeoff, analogous to
+/// rollback netcodes: a higher `input_delay_ticks` trades added input latency for
+/// fewer mispredictions, while `max_prediction_window` bounds how far the client
+/// is allowed to simulate ahead of the last server-confirmed tick before stalling
+/// (enforced by `stall_past_prediction_window`).
+#[derive(Resource, Clone, Copy)]
+struct PredictionTuning {
+    input_delay_ticks: u16,
+    max_prediction_window: u16,
+}
+
+impl Default for PredictionTuning {
+    fn default() -> Self {
+        Self {
+            input_delay_ticks: 3,
+            max_prediction_window: 16,
+        }
+    }
+}
+
+/// Freezes the fixed-timestep virtual clock whenever the client has simulated
+/// more than `max_prediction_window` ticks ahead of the last server-confirmed
+/// one, so a stalled connection can't let prediction run away unbounded; the
+/// clock resumes once confirmation catches back up. Requires a real `Confirmed`
+/// player to measure the gap against — under this example's sole
+/// `Mode::HostServer` run the host's own player never gets one (see
+/// `warn_sync_test_host_server_limitation`), so the window is structurally
+/// never exceeded here and this system is a dormant safety net until a
+/// non-host client connects.
+fn stall_past_prediction_window(
+    tick_manager: Res<TickManager>,
+    tuning: Res<PredictionTuning>,
+    confirmed: Query<&Confirmed, With<Player>>,
+    mut time: ResMut<Time<Virtual>>,
+) {
+    let tick = tick_manager.tick();
+    let simulated_ahead = confirmed
+        .iter()
+        .map(|confirmed| tick - confirmed.tick)
+        .max()
+        .unwrap_or(0);
+
+    if simulated_ahead > tuning.max_prediction_window as i16 {
+        time.set_relative_speed(0.0);
+    } else {
+        time.set_relative_speed(1.0);
+    }
+}
+
+/// A snapshot of the parts of `ActionState<PlayerActions>` that `apply_actions`
+/// cares about, cheap to copy into the delayed ring buffer every tick.
+#[derive(Clone, Copy, Default)]
+struct PlayerActionSnapshot {
+    up: bool,
+    down: bool,
+    left: bool,
+    right: bool,
+    look: Vec2,
+}
+
+impl From<&ActionState<PlayerActions>> for PlayerActionSnapshot {
+    fn from(action: &ActionState<PlayerActions>) -> Self {
+        Self {
+            up: action.pressed(&PlayerActions::Up),
+            down: action.pressed(&PlayerActions::Down),
+            left: action.pressed(&PlayerActions::Left),
+            right: action.pressed(&PlayerActions::Right),
+            look: action
+                .axis_pair(&PlayerActions::Look)
+                .map(|axis| axis.xy())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Per-tick ring buffer of sampled inputs, keyed by the tick they were sampled on
+/// but only meant to be *applied* `input_delay_ticks` later. Capacity is
+/// `max_prediction_window + input_delay_ticks` (see `sample_delayed_input`) so
+/// replay during rollback can always find the delayed input for any tick the
+/// client might need to re-simulate.
+#[derive(Component, Default)]
+struct DelayedInputBuffer {
+    entries: VecDeque<(Tick, PlayerActionSnapshot)>,
+}
+
+impl DelayedInputBuffer {
+    /// Inserts or, if `tick` is already present, overwrites in place. Rollback
+    /// re-simulation re-samples ticks already in the buffer; without the
+    /// overwrite this would push duplicate entries for the same tick and let
+    /// front-eviction discard the live one while a stale duplicate survives.
+    fn push(&mut self, tick: Tick, snapshot: PlayerActionSnapshot, capacity: u16) {
+        if let Some(entry) = self.entries.iter_mut().find(|(t, _)| *t == tick) {
+            entry.1 = snapshot;
+            return;
+        }
+
+        self.entries.push_back((tick, snapshot));
+        while self.entries.len() > capacity as usize {
+            self.entries.pop_front();
+        }
+    }
+
+    fn get(&self, tick: Tick) -> Option<PlayerActionSnapshot> {
+        self.entries
+            .iter()
+            .find(|(t, _)| *t == tick)
+            .map(|(_, snapshot)| *snapshot)
+    }
+}
+
+/// Samples the live `ActionState` for the current tick and stores it in the
+/// delayed ring buffer for application `input_delay_ticks` ticks from now.
+fn sample_delayed_input(
+    tick_manager: Res<TickManager>,
+    tuning: Res<PredictionTuning>,
+    mut query: Query<(&ActionState<PlayerActions>, &mut DelayedInputBuffer)>,
+) {
+    let apply_tick = tick_manager.tick() + tuning.input_delay_ticks;
+    // Entries are keyed by `apply_tick`, i.e. `input_delay_ticks` ahead of the
+    // tick they were sampled on, so the buffer must hold that far ahead *plus*
+    // the full prediction window behind it or a rollback spanning the whole
+    // window would evict the oldest delayed inputs before they're ever read.
+    let capacity = tuning.max_prediction_window + tuning.input_delay_ticks;
+    for (action, mut buffer) in query.iter_mut() {
+        let snapshot = PlayerActionSnapshot::from(action);
+        buffer.push(apply_tick, snapshot, capacity);
+    }
+}
+
+/// Seeds xpbd's own `Position`/`Rotation` from the (possibly just-corrected)
+/// `Transform` so a rollback re-simulation starts the physics step from the
+/// right state instead of whatever the last live step left behind.
+fn sync_physics_from_transform(
+    mut query: Query<(&Transform, &mut Position, &mut Rotation), With<Player>>,
+) {
+    for (transform, mut position, mut rotation) in query.iter_mut() {
+        position.0 = transform.translation;
+        *rotation = Rotation::from(transform.rotation);
+    }
 }
 
 fn apply_actions(
-    mut query: Query<(&ActionState<PlayerActions>, &mut Transform), With<Player>>,
+    tick_manager: Res<TickManager>,
+    mut query: Query<(&DelayedInputBuffer, &mut LinearVelocity, &mut Rotation), With<Player>>,
     time: Res<Time>,
 ) {
     const MOVE_SPEED: f32 = 15.0;
-    for (action, mut transform) in query.iter_mut() {
+    let tick = tick_manager.tick();
+    for (buffer, mut velocity, mut rotation) in query.iter_mut() {
+        // On the first `input_delay_ticks` ticks the buffer has nothing queued yet
+        // for this tick; fall back to a neutral action rather than stalling.
+        let action = buffer.get(tick).unwrap_or_default();
+
         let mut direction = Vec3::ZERO;
-        if action.pressed(&PlayerActions::Up) {
+        if action.up {
             direction.z -= 1.0;
         }
 
-        if action.pressed(&PlayerActions::Down) {
+        if action.down {
             direction.z += 1.0;
         }
 
-        if action.pressed(&PlayerActions::Left) {
+        if action.left {
             direction.x -= 1.0;
         }
 
-        if action.pressed(&PlayerActions::Right) {
+        if action.right {
             direction.x += 1.0;
         }
 
-        let delta = (transform.rotation * direction.normalize_or_zero()).normalize_or_zero()
-            * MOVE_SPEED
-            * time.delta_seconds();
+        let horizontal = (Quat::from(*rotation) * direction.normalize_or_zero()).normalize_or_zero()
+            * MOVE_SPEED;
 
-        transform.translation += delta;
+        velocity.x = horizontal.x;
+        velocity.z = horizontal.z;
 
         const SENSITIVE: f32 = 2.;
-        let Some(event) = action.axis_pair(&PlayerActions::Look) else {
+        if action.look != Vec2::ZERO {
+            let yaw = (-action.look.x) * SENSITIVE * time.delta_seconds();
+
+            *rotation *= Rotation::from(Quat::from_rotation_y(yaw.to_radians()));
+        }
+    }
+}
+
+/// Opt-in sync-test mode: each confirmed tick, checksums the predicted simulation
+/// against the server-confirmed snapshot for the same tick and logs any divergence.
+/// `quantization_epsilon` rounds floats to a fixed grid before hashing so that
+/// acceptable FP noise between the two timelines doesn't trip false positives.
+/// Requires a real Predicted/Confirmed split to compare anything; this example's
+/// sole `Mode::HostServer` run never produces one for the host's own player, so
+/// enabling this without a second, non-host client connected is a no-op — see
+/// `warn_sync_test_host_server_limitation`.
+#[derive(Resource, Clone, Copy)]
+struct SyncTestConfig {
+    enabled: bool,
+    quantization_epsilon: f32,
+}
+
+impl Default for SyncTestConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            quantization_epsilon: 0.001,
+        }
+    }
+}
+
+#[derive(Resource, Default)]
+struct PredictedChecksums(HashMap<Tick, u64>);
+
+#[derive(Resource, Default)]
+struct ConfirmedChecksums(HashMap<Tick, u64>);
+
+fn quantize(value: f32, epsilon: f32) -> i64 {
+    (value / epsilon).round() as i64
+}
+
+/// Hashes the quantized `Transform` translation/rotation only. The other
+/// components registered with `ComponentSyncMode::Full` prediction
+/// (`LinearVelocity`, `AngularVelocity`, `Position`, `Rotation`) are not part of
+/// the checksum.
+fn checksum_transform(transform: &Transform, epsilon: f32) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    quantize(transform.translation.x, epsilon).hash(&mut hasher);
+    quantize(transform.translation.y, epsilon).hash(&mut hasher);
+    quantize(transform.translation.z, epsilon).hash(&mut hasher);
+    quantize(transform.rotation.x, epsilon).hash(&mut hasher);
+    quantize(transform.rotation.y, epsilon).hash(&mut hasher);
+    quantize(transform.rotation.z, epsilon).hash(&mut hasher);
+    quantize(transform.rotation.w, epsilon).hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Only matches a real `Predicted` shadow entity. Earlier this also fell back
+/// to the host's own (un-split) player so the map was never empty, but that
+/// made `checksum_confirmed_state` read the *same* entity at the *same* tick —
+/// a tautology that can never detect divergence. Staying strict means this
+/// harness is genuinely empty until a non-host client connects and gets a
+/// Predicted/Confirmed split, which is honest: see
+/// `warn_sync_test_host_server_limitation`.
+fn checksum_predicted_state(
+    tick_manager: Res<TickManager>,
+    config: Res<SyncTestConfig>,
+    mut checksums: ResMut<PredictedChecksums>,
+    query: Query<&Transform, (With<Player>, With<Predicted>)>,
+) {
+    let tick = tick_manager.tick();
+    for transform in query.iter() {
+        checksums
+            .0
+            .insert(tick, checksum_transform(transform, config.quantization_epsilon));
+    }
+}
+
+/// Only matches a real `Confirmed` entity, at its own confirmed tick — see
+/// `checksum_predicted_state` for why this no longer falls back to the host's
+/// own player.
+fn checksum_confirmed_state(
+    config: Res<SyncTestConfig>,
+    mut checksums: ResMut<ConfirmedChecksums>,
+    query: Query<(&Transform, &Confirmed), With<Player>>,
+) {
+    for (transform, confirmed) in query.iter() {
+        checksums.0.insert(
+            confirmed.tick,
+            checksum_transform(transform, config.quantization_epsilon),
+        );
+    }
+}
+
+/// `Mode::HostServer` never splits the host's own player into a
+/// Predicted/Confirmed pair (it *is* the authoritative state, with zero
+/// latency to reconcile), so `checksum_predicted_state`/`checksum_confirmed_state`
+/// have nothing to match in the only mode this example runs. Say so loudly
+/// instead of silently doing nothing, so enabling sync-test mode here doesn't
+/// read as "passing" when it never actually compared anything.
+fn warn_sync_test_host_server_limitation(config: Res<SyncTestConfig>) {
+    if config.enabled {
+        warn!(
+            "sync-test mode is enabled, but this example only runs Mode::HostServer, where the \
+             host's own player never gets split into a Predicted/Confirmed pair; no checksums \
+             will be compared until a real, non-host client connects"
+        );
+    }
+}
+
+/// Reconciles the predicted and confirmed checksum timelines as confirmed
+/// snapshots arrive, logging the tick, both checksums and the inputs that
+/// produced them whenever the two diverge. Only the checksums are stored, so
+/// per-component deltas are not logged.
+fn reconcile_sync_test_checksums(
+    mut predicted: ResMut<PredictedChecksums>,
+    mut confirmed: ResMut<ConfirmedChecksums>,
+    inputs: Query<&DelayedInputBuffer, With<Player>>,
+) {
+    let mut reconciled_ticks: Vec<Tick> = confirmed
+        .0
+        .keys()
+        .filter(|tick| predicted.0.contains_key(tick))
+        .copied()
+        .collect();
+    // Oldest first, so the pruning below never drops a tick still to be reconciled.
+    reconciled_ticks.sort_by(|a, b| (*a - *b).cmp(&0));
+
+    for tick in reconciled_ticks {
+        let predicted_checksum = predicted.0.remove(&tick).unwrap();
+        let confirmed_checksum = confirmed.0.remove(&tick).unwrap();
+
+        if predicted_checksum != confirmed_checksum {
+            let PlayerActionSnapshot { up, down, left, right, look } = inputs
+                .iter()
+                .find_map(|buffer| buffer.get(tick))
+                .unwrap_or_default();
+            error!(
+                ?tick,
+                predicted_checksum,
+                confirmed_checksum,
+                up,
+                down,
+                left,
+                right,
+                ?look,
+                "prediction diverged from server-confirmed state"
+            );
+        }
+
+        // Ticks older than this one are no longer reachable by the confirmed
+        // timeline; drop them so the maps don't grow unbounded. `Tick` wraps,
+        // so pruning must go through subtraction (wrapping-correct) rather
+        // than `>=`/`<`, which can misorder ticks near the wrap boundary.
+        predicted.0.retain(|t, _| *t - tick >= 0);
+        confirmed.0.retain(|t, _| *t - tick >= 0);
+    }
+}
+
+/// Whether the server rewinds entities to the tick a client actually saw before
+/// resolving position-sensitive actions (e.g. `PlayerActions::Fire`), and how
+/// far back it is willing to rewind.
+#[derive(Resource, Clone, Copy)]
+struct LagCompensationConfig {
+    enabled: bool,
+    max_compensation_ticks: u16,
+}
+
+impl Default for LagCompensationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_compensation_ticks: 32,
+        }
+    }
+}
+
+/// A client's current round-trip time and interpolation delay, expressed in
+/// ticks. Tracked as a plain component (rather than read from the connection
+/// inline at resolve time) so the rewind math doesn't need to special-case how
+/// a given client's latency was measured.
+#[derive(Component, Default)]
+struct LagEstimate {
+    round_trip_ticks: u16,
+    interpolation_delay_ticks: u16,
+}
+
+/// Refreshes `LagEstimate` from the connection's real ping/interpolation stats
+/// every tick, so `resolve_fire_lag_compensated` rewinds by what the client
+/// actually experiences instead of a permanent zero.
+///
+/// This reads the *client*'s own `ConnectionManager`, which only ever reports
+/// that client's own round trip to the server — a true server-side lag
+/// compensation system would instead track each connected client's RTT
+/// separately and rewind per-shooter. In this example's sole `Mode::HostServer`
+/// run the host is both client and server with no network hop between them, so
+/// `connection.rtt()`/`interpolation_delay()` collapse to ~0 and
+/// `resolve_fire_lag_compensated` rewinds by ~0 ticks — a structural no-op here,
+/// not a bug in the estimate itself.
+fn update_lag_estimate(
+    tick_manager: Res<TickManager>,
+    connection: Res<ConnectionManager>,
+    mut query: Query<&mut LagEstimate, With<Player>>,
+) {
+    let round_trip_ticks = tick_manager.duration_to_ticks(connection.rtt());
+    let interpolation_delay_ticks =
+        tick_manager.duration_to_ticks(connection.interpolation_delay());
+
+    for mut lag in query.iter_mut() {
+        lag.round_trip_ticks = round_trip_ticks;
+        lag.interpolation_delay_ticks = interpolation_delay_ticks;
+    }
+}
+
+/// Ring buffer of an entity's `Transform` per recent server tick, bounded by
+/// `LagCompensationConfig::max_compensation_ticks`.
+#[derive(Component, Default)]
+struct TransformHistory {
+    entries: VecDeque<(Tick, Transform)>,
+}
+
+impl TransformHistory {
+    fn push(&mut self, tick: Tick, transform: Transform, capacity: u16) {
+        self.entries.push_back((tick, transform));
+        while self.entries.len() > capacity as usize {
+            self.entries.pop_front();
+        }
+    }
+
+    /// Interpolates between the two stored ticks nearest `target_tick`,
+    /// clamping to the oldest retained tick if the history doesn't reach back
+    /// that far.
+    fn sample(&self, target_tick: Tick) -> Option<Transform> {
+        // `Tick` wraps, so bracketing must go through subtraction rather than
+        // raw `<`/`>`/`==` — the same idiom `reconcile_sync_test_checksums`
+        // uses to prune its checksum maps safely across a wrap boundary.
+        let oldest = self.entries.front()?.0;
+        let target_tick = if target_tick - oldest < 0 { oldest } else { target_tick };
+
+        if let Some((_, transform)) = self.entries.iter().find(|(t, _)| *t - target_tick == 0) {
+            return Some(*transform);
+        }
+
+        let before = self.entries.iter().rev().find(|(t, _)| *t - target_tick < 0);
+        let after = self.entries.iter().find(|(t, _)| *t - target_tick > 0);
+
+        match (before, after) {
+            (Some((before_tick, before_transform)), Some((after_tick, after_transform))) => {
+                let span = (*after_tick - *before_tick) as f32;
+                let alpha = (target_tick - *before_tick) as f32 / span;
+                Some(Transform {
+                    translation: before_transform
+                        .translation
+                        .lerp(after_transform.translation, alpha),
+                    rotation: before_transform.rotation.slerp(after_transform.rotation, alpha),
+                    scale: before_transform.scale,
+                })
+            }
+            (Some((_, transform)), None) | (None, Some((_, transform))) => Some(*transform),
+            (None, None) => None,
+        }
+    }
+}
+
+fn record_transform_history(
+    tick_manager: Res<TickManager>,
+    config: Res<LagCompensationConfig>,
+    mut query: Query<(&Transform, &mut TransformHistory), With<Player>>,
+) {
+    let tick = tick_manager.tick();
+    for (transform, mut history) in query.iter_mut() {
+        history.push(tick, *transform, config.max_compensation_ticks);
+    }
+}
+
+fn ray_hits_sphere(origin: Vec3, direction: Vec3, center: Vec3, radius: f32) -> bool {
+    let to_center = center - origin;
+    let closest = origin + direction * to_center.dot(direction).max(0.0);
+    closest.distance(center) <= radius
+}
+
+/// Rewinds every other replicated entity to the tick the firing client actually
+/// saw (derived from their RTT + interpolation delay) before resolving the
+/// raycast, then discards the rewound state without touching the authoritative
+/// `Transform`.
+fn resolve_fire_lag_compensated(
+    tick_manager: Res<TickManager>,
+    shooters: Query<(Entity, &ActionState<PlayerActions>, &Transform, &LagEstimate), With<Player>>,
+    targets: Query<(Entity, &TransformHistory), With<Player>>,
+) {
+    const PLAYER_HIT_RADIUS: f32 = 1.0;
+
+    let server_tick = tick_manager.tick();
+    for (shooter_entity, action, shooter_transform, lag) in shooters.iter() {
+        if !action.just_pressed(&PlayerActions::Fire) {
             continue;
-        };
+        }
+
+        let rewind_ticks = lag.round_trip_ticks + lag.interpolation_delay_ticks;
+        let target_tick = server_tick - rewind_ticks;
+        let origin = shooter_transform.translation;
+        let direction = shooter_transform.forward().as_vec3();
+
+        for (target_entity, history) in targets.iter() {
+            if target_entity == shooter_entity {
+                continue;
+            }
+
+            let Some(rewound) = history.sample(target_tick) else {
+                continue;
+            };
+
+            if ray_hits_sphere(origin, direction, rewound.translation, PLAYER_HIT_RADIUS) {
+                info!(
+                    ?shooter_entity,
+                    ?target_entity,
+                    ?target_tick,
+                    "lag-compensated hit"
+                );
+            }
+        }
+    }
+}
+
+/// A client-local camera rig following a `Player` entity. Yaw stays on the
+/// replicated player body (predicted like today); pitch and zoom live here,
+/// on an entity that is never replicated, so look state can't get entangled
+/// with network sync.
+#[derive(Component)]
+struct CameraRig {
+    follow: Entity,
+    pitch: f32,
+    zoom: f32,
+}
+
+impl CameraRig {
+    const DEFAULT_ZOOM: f32 = 10.0;
+    const MIN_ZOOM: f32 = 4.0;
+    const MAX_ZOOM: f32 = 20.0;
+    // ±89°, in radians, to avoid gimbal flip at the poles.
+    const MAX_PITCH: f32 = 89.0 * std::f32::consts::PI / 180.0;
+    const ZOOM_SPEED: f32 = 2.0;
+    const PITCH_SENSITIVITY: f32 = 2.0;
+}
 
-        if event.xy() != Vec2::ZERO {
-            let event = event.xy();
-            let yaw = (-event.x) * SENSITIVE * time.delta_seconds();
-            // let pitch = event.y * SENSITIVE * time.delta_seconds();
+/// Reads the rig's own pitch/zoom state directly from the live `ActionState` of
+/// the player it follows; this is client-local view state, not predicted
+/// simulation state, so it isn't routed through `DelayedInputBuffer`.
+fn update_camera_rig_look(
+    players: Query<&ActionState<PlayerActions>, With<Player>>,
+    mut rigs: Query<&mut CameraRig>,
+    time: Res<Time>,
+) {
+    for mut rig in rigs.iter_mut() {
+        let Ok(action) = players.get(rig.follow) else {
+            continue;
+        };
 
-            transform.rotation *= Quat::from_rotation_y(yaw.to_radians());
+        if let Some(look) = action.axis_pair(&PlayerActions::Look) {
+            let pitch_delta = look.xy().y * CameraRig::PITCH_SENSITIVITY * time.delta_seconds();
+            rig.pitch = (rig.pitch + pitch_delta).clamp(-CameraRig::MAX_PITCH, CameraRig::MAX_PITCH);
         }
+
+        let zoom_delta = action.value(&PlayerActions::Zoom) * CameraRig::ZOOM_SPEED;
+        rig.zoom = (rig.zoom - zoom_delta).clamp(CameraRig::MIN_ZOOM, CameraRig::MAX_ZOOM);
+    }
+}
+
+/// Follows the local, predicted player's own `Transform` directly (zero extra
+/// latency, since it's the rig owner's own prediction), but falls back to a
+/// followed entity's `Confirmed`/interpolated transform otherwise — that's the
+/// path a rig following a remote player would take. This example's sole
+/// `Mode::HostServer` run never splits the host's own player into a
+/// Predicted/Confirmed pair (see `warn_sync_test_host_server_limitation`), so
+/// `host_player` is the fallback that keeps the rig working in that case.
+/// Position eases toward the target each frame so the view doesn't hard-snap
+/// on top of the player's own prediction corrections; the rig's rotation
+/// (player yaw plus pitch) applies immediately, while zoom only moves the
+/// target offset and so eases in along with position.
+fn camera_follow(
+    mut rigs: Query<(&CameraRig, &mut Transform), Without<Player>>,
+    local_predicted: Query<&Transform, (With<Player>, With<Predicted>)>,
+    confirmed: Query<&Transform, (With<Player>, With<Confirmed>)>,
+    host_player: Query<&Transform, (With<Player>, Without<Predicted>, Without<Confirmed>)>,
+    time: Res<Time>,
+) {
+    const EYE_HEIGHT: f32 = 1.5;
+    // How quickly the rig's position eases toward the player; higher is snappier.
+    const FOLLOW_RATE: f32 = 12.0;
+
+    for (rig, mut transform) in rigs.iter_mut() {
+        let Ok(player_transform) = local_predicted
+            .get(rig.follow)
+            .or_else(|_| confirmed.get(rig.follow))
+            .or_else(|_| host_player.get(rig.follow))
+        else {
+            continue;
+        };
+
+        let rotation = player_transform.rotation * Quat::from_rotation_x(rig.pitch);
+        let offset = rotation * Vec3::new(0.0, 0.0, rig.zoom);
+        let target = player_transform.translation + Vec3::Y * EYE_HEIGHT + offset;
+
+        let smoothing = 1.0 - (-FOLLOW_RATE * time.delta_seconds()).exp();
+        transform.translation = transform.translation.lerp(target, smoothing);
+        transform.rotation = rotation;
     }
 }
 
@@ -187,4 +824,6 @@ enum PlayerActions {
     Left,
     Right,
     Look,
+    Fire,
+    Zoom,
 }